@@ -1,18 +1,301 @@
+use std::any::Any;
 use std::cmp::Eq;
 use std::fmt::Display;
 use std::iter::FromIterator;
 
-pub trait Parser<T: Display + Eq, E>: Sized {
+/// A cheap membership set over the token type, used to tell recovering
+/// combinators where the next well-formed construct is allowed to start.
+pub struct TokenSet<T> {
+    tokens: Vec<T>,
+}
+
+impl<T: Eq> TokenSet<T> {
+    pub fn new(tokens: Vec<T>) -> TokenSet<T> {
+        TokenSet { tokens: tokens }
+    }
+
+    pub fn contains(&self, token: &T) -> bool {
+        self.tokens.iter().any(|x| x == token)
+    }
+}
+
+/// How seriously a `Diagnostic` should be taken; errors block a clean parse,
+/// warnings don't.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A positioned problem collected during parsing. Unlike the single `E`
+/// returned by a hard failure, diagnostics accumulate so a whole parse can
+/// report every problem it found, not just the first.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub pos: (i32, i32),
+    pub message: String,
+    pub severity: Severity,
+}
+
+/// The cached outcome of a memoized rule at a given position: either it
+/// succeeded, ending at `end_pos` with a clonable result, or it failed.
+/// Besides the cursor, `events`/`diagnostics` hold whatever the live run
+/// recorded, so a cache hit replays those side effects instead of just
+/// the end position.
+pub enum MemoOutcome<K> {
+    Success {
+        end_pos: usize,
+        value: Box<Any>,
+        events: Vec<Event<K>>,
+        diagnostics: Vec<Diagnostic>,
+    },
+    Failure {
+        end_pos: usize,
+        err: Box<Any>,
+        events: Vec<Event<K>>,
+        diagnostics: Vec<Diagnostic>,
+    },
+}
+
+/// One step of a flat, lossless event stream: a (possibly still-tombstoned)
+/// node start, a consumed token, or the close of the innermost open node.
+/// A later pass folds this into a concrete syntax tree.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Event<K> {
+    Start {
+        kind: Option<K>,
+        forward_parent: Option<usize>,
+    },
+    Token,
+    Finish,
+}
+
+/// An open, not-yet-typed node in the event stream. Consumed by `complete`
+/// (to give it a kind) or `abandon` (to drop it as unused).
+pub struct Marker {
+    pos: usize,
+}
+
+impl Marker {
+    fn new(pos: usize) -> Marker {
+        Marker { pos: pos }
+    }
+
+    pub fn complete<T, E, K, P>(self, parser: &mut P, kind: K) -> CompletedMarker
+        where P: Parser<T, E, K>,
+              T: Display + Eq
+    {
+        if let &mut Event::Start { kind: ref mut k, .. } = parser.event_mut(self.pos) {
+            *k = Some(kind);
+        }
+        parser.push_event(Event::Finish);
+        CompletedMarker::new(self.pos)
+    }
+
+    /// Turns this marker into a no-op. If nothing was parsed since `start()`,
+    /// the tombstoned `Start` is removed outright; otherwise it's balanced
+    /// with a `Finish` so the event stream stays well-nested, and the
+    /// tree-building pass is left to drop the (kindless) node while keeping
+    /// its children.
+    pub fn abandon<T, E, K, P>(self, parser: &mut P)
+        where P: Parser<T, E, K>,
+              T: Display + Eq
+    {
+        if self.pos == parser.events_len() - 1 {
+            parser.truncate_events(self.pos);
+        } else {
+            parser.push_event(Event::Finish);
+        }
+    }
+}
+
+/// A node that already has a kind. `precede` lets a later marker wrap it in
+/// a new parent without having opened that parent up front.
+pub struct CompletedMarker {
+    pos: usize,
+}
+
+impl CompletedMarker {
+    fn new(pos: usize) -> CompletedMarker {
+        CompletedMarker { pos: pos }
+    }
+
+    pub fn precede<T, E, K, P>(self, parser: &mut P) -> Marker
+        where P: Parser<T, E, K>,
+              T: Display + Eq
+    {
+        let new_marker = parser.start();
+        if let &mut Event::Start { ref mut forward_parent, .. } = parser.event_mut(self.pos) {
+            *forward_parent = Some(new_marker.pos);
+        }
+        new_marker
+    }
+}
+
+pub trait Parser<T: Display + Eq, E, K>: Sized {
     fn preview(&self) -> Option<&T>;
     fn consume(&mut self) -> Option<T>;
     fn current_pos(&self) -> (i32, i32);
     fn error<S: Into<String>>(&self, message: S) -> E;
 
+    /// Pushes the current position, and the current length of the event
+    /// and diagnostic logs, onto a checkpoint stack. Every `save` must be
+    /// paired with exactly one `load` (restore cursor, truncate both logs
+    /// back to their snapshotted lengths, and pop) or `commit` (pop only),
+    /// so nested `try`/`choose`/`many` can backtrack without leaking a
+    /// discarded branch's tokens or diagnostics into the lossless streams.
     fn save(&mut self);
     fn load(&mut self);
+    fn commit(&mut self);
+
+    fn push_event(&mut self, event: Event<K>) -> usize;
+    fn event_mut(&mut self, pos: usize) -> &mut Event<K>;
+    fn events_len(&self) -> usize;
+    fn event_at(&self, pos: usize) -> &Event<K>;
+    fn truncate_events(&mut self, len: usize);
+
+    fn push_diagnostic(&mut self, diagnostic: Diagnostic);
+    fn take_diagnostics(&mut self) -> Vec<Diagnostic>;
+    fn diagnostics_len(&self) -> usize;
+    fn diagnostic_at(&self, pos: usize) -> &Diagnostic;
+    fn truncate_diagnostics(&mut self, len: usize);
+
+    /// The parser's current stream offset, used as half of the packrat
+    /// memoization key and as the fast-forward target on a cache hit.
+    fn mark(&self) -> usize;
+
+    /// Jumps the cursor directly to `pos`, bypassing the checkpoint stack.
+    fn seek(&mut self, pos: usize);
+
+    fn memo_lookup(&self, rule_id: u32, pos: usize) -> Option<&MemoOutcome<K>>;
+    fn memo_store(&mut self, rule_id: u32, pos: usize, outcome: MemoOutcome<K>);
+
+    /// Packrat memoization: caches the outcome of `parser` at `(rule_id,
+    /// current position)` so a second attempt at the same rule and position
+    /// is an O(1) lookup instead of a full reparse. A cache hit replays the
+    /// events and diagnostics the live run recorded, not just the cursor.
+    fn memoize<O, F>(&mut self, rule_id: u32, parser: F) -> Result<O, E>
+        where F: Fn(&mut Self) -> Result<O, E>,
+              O: Clone + 'static,
+              E: Clone + 'static,
+              K: Clone
+    {
+        let pos = self.mark();
+        let cached = match self.memo_lookup(rule_id, pos) {
+            Some(&MemoOutcome::Success { end_pos, ref value, ref events, ref diagnostics }) => {
+                let v = value.downcast_ref::<O>().expect("memoize: cached type mismatch").clone();
+                Some((end_pos, events.clone(), diagnostics.clone(), Ok(v)))
+            }
+            Some(&MemoOutcome::Failure { end_pos, ref err, ref events, ref diagnostics }) => {
+                let e = err.downcast_ref::<E>().expect("memoize: cached type mismatch").clone();
+                Some((end_pos, events.clone(), diagnostics.clone(), Err(e)))
+            }
+            None => None,
+        };
+        if let Some((end_pos, events, diagnostics, outcome)) = cached {
+            // `forward_parent` indices were captured relative to the start
+            // of this span, so they need shifting to wherever the replayed
+            // events actually land this time.
+            let new_start = self.events_len();
+            for mut event in events {
+                if let Event::Start { ref mut forward_parent, .. } = event {
+                    *forward_parent = forward_parent.map(|fp| fp + new_start);
+                }
+                self.push_event(event);
+            }
+            for diagnostic in diagnostics {
+                self.push_diagnostic(diagnostic);
+            }
+            self.seek(end_pos);
+            return outcome;
+        }
+
+        let events_start = self.events_len();
+        let diagnostics_start = self.diagnostics_len();
+        let result = parser(self);
+        let end_pos = self.mark();
+        let events: Vec<Event<K>> = (events_start..self.events_len())
+            .map(|i| {
+                let mut event = self.event_at(i).clone();
+                if let Event::Start { ref mut forward_parent, .. } = event {
+                    *forward_parent = forward_parent.map(|fp| fp - events_start);
+                }
+                event
+            })
+            .collect();
+        let diagnostics: Vec<Diagnostic> = (diagnostics_start..self.diagnostics_len())
+            .map(|i| self.diagnostic_at(i).clone())
+            .collect();
+        match result {
+            Ok(ref v) => {
+                self.memo_store(rule_id,
+                                 pos,
+                                 MemoOutcome::Success {
+                                     end_pos: end_pos,
+                                     value: Box::new(v.clone()),
+                                     events: events,
+                                     diagnostics: diagnostics,
+                                 })
+            }
+            Err(ref e) => {
+                self.memo_store(rule_id,
+                                 pos,
+                                 MemoOutcome::Failure {
+                                     end_pos: end_pos,
+                                     err: Box::new(e.clone()),
+                                     events: events,
+                                     diagnostics: diagnostics,
+                                 })
+            }
+        }
+        result
+    }
+
+    /// Stamps `message` with `current_pos()` and appends it to the
+    /// diagnostic list, without producing a hard failure.
+    fn emit<S: Into<String>>(&mut self, message: S) {
+        let pos = self.current_pos();
+        self.push_diagnostic(Diagnostic {
+                                  pos: pos,
+                                  message: message.into(),
+                                  severity: Severity::Error,
+                              });
+    }
+
+    /// Runs a top-level grammar entry point and turns its on-first-error
+    /// `Result` into the batch-of-problems shape a caller (e.g. a REPL)
+    /// actually wants: the parsed output, if any, alongside every
+    /// diagnostic the recovery variants collected along the way.
+    fn parse<O, F>(&mut self, parser: F) -> (Option<O>, Vec<Diagnostic>)
+        where F: Fn(&mut Self) -> Result<O, E>,
+              E: Display
+    {
+        let output = match parser(self) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                self.emit(e.to_string());
+                None
+            }
+        };
+        (output, self.take_diagnostics())
+    }
+
+    fn start(&mut self) -> Marker {
+        let pos = self.push_event(Event::Start {
+                                       kind: None,
+                                       forward_parent: None,
+                                   });
+        Marker::new(pos)
+    }
 
     fn next(&mut self) -> Result<T, E> {
-        self.consume().ok_or(self.error("unexpected eof"))
+        match self.consume() {
+            Some(x) => {
+                self.push_event(Event::Token);
+                Ok(x)
+            }
+            None => Err(self.error("unexpected eof")),
+        }
     }
 
     fn predicate<F>(&mut self, pred: F) -> Result<T, E>
@@ -53,10 +336,16 @@ pub trait Parser<T: Display + Eq, E>: Sized {
         where F: Fn(&mut Self) -> Result<O, E>
     {
         self.save();
-        parser(self).map_err(|x| {
-                                 self.load();
-                                 x
-                             })
+        match parser(self) {
+            Ok(x) => {
+                self.commit();
+                Ok(x)
+            }
+            Err(x) => {
+                self.load();
+                Err(x)
+            }
+        }
     }
 
     fn choose<O>(&mut self, parsers: &[&Fn(&mut Self) -> Result<O, E>]) -> Result<O, E> {
@@ -107,16 +396,125 @@ pub trait Parser<T: Display + Eq, E>: Sized {
     {
         let _ = self.try(parser);
     }
+
+    fn at_set(&self, set: &TokenSet<T>) -> bool {
+        match self.preview() {
+            Some(x) => set.contains(x),
+            None => false,
+        }
+    }
+
+    /// Records an error at the current position, then discards tokens until
+    /// `preview()` lands in `recovery` (or EOF), so the caller can keep
+    /// parsing instead of unwinding the whole grammar on a single bad token.
+    fn err_recover<S: Into<String>>(&mut self, message: S, recovery: &TokenSet<T>) -> E {
+        let message = message.into();
+        self.emit(message.clone());
+        let err = self.error(message);
+        while !self.at_set(recovery) {
+            if self.next().is_err() {
+                break;
+            }
+        }
+        err
+    }
+
+    /// Precedence-climbing expression parser. `prefix` parses an atom or
+    /// unary operator; `infix_bp` maps a lookahead operator token to its
+    /// `(left, right)` binding powers (`None` if it isn't an infix operator
+    /// here); `fold` combines a parsed `lhs op rhs` into a single `O`.
+    /// Right-associativity falls out of `right_bp < left_bp`,
+    /// left-associativity from `right_bp > left_bp`.
+    // Recursing through `self.expr_bp(..., &prefix, &infix_bp, &fold)` would wrap the
+    // closures in a fresh reference at every nesting level, so the compiler has to
+    // monomorphize a distinct `expr_bp::<_, &P1, ...>`, `expr_bp::<_, &&P1, ...>`, ...
+    // for each level of infix nesting - that never terminates. Track pending operators
+    // and their right binding power on an explicit stack instead, so there is only ever
+    // one instantiation of this function.
+    fn expr_bp<O, P1, IB, F>(&mut self, min_bp: u8, prefix: P1, infix_bp: IB, fold: F) -> Result<O, E>
+        where P1: Fn(&mut Self) -> Result<O, E>,
+              IB: Fn(&T) -> Option<(u8, u8)>,
+              F: Fn(O, T, O) -> O
+    {
+        let mut operands: Vec<O> = vec![try!(prefix(self))];
+        let mut operators: Vec<(T, u8)> = Vec::new();
+
+        loop {
+            let bp = match self.preview() {
+                Some(op) => infix_bp(op),
+                None => None,
+            };
+            let (l_bp, r_bp) = match bp {
+                Some(x) => x,
+                None => break,
+            };
+
+            while let Some(&(_, floor)) = operators.last() {
+                if l_bp < floor {
+                    let (op, _) = operators.pop().expect("operators checked non-empty above");
+                    let rhs = operands.pop().expect("rhs pushed alongside each operator");
+                    let lhs = operands.pop().expect("lhs present below each operator's rhs");
+                    operands.push(fold(lhs, op, rhs));
+                } else {
+                    break;
+                }
+            }
+
+            if operators.is_empty() && l_bp < min_bp {
+                break;
+            }
+
+            let op = try!(self.next());
+            let rhs = try!(prefix(self));
+            operands.push(rhs);
+            operators.push((op, r_bp));
+        }
+
+        while let Some((op, _)) = operators.pop() {
+            let rhs = operands.pop().expect("rhs pushed alongside each operator");
+            let lhs = operands.pop().expect("lhs present below each operator's rhs");
+            operands.push(fold(lhs, op, rhs));
+        }
+
+        Ok(operands.pop().expect("prefix() seeded at least one operand"))
+    }
+
+    fn many_recover<X, F, O>(&mut self, parser: F, recovery: &TokenSet<T>) -> O
+        where F: Fn(&mut Self) -> Result<X, E>,
+              O: FromIterator<X>
+    {
+        let mut res: Vec<X> = Vec::new();
+        loop {
+            if self.preview().is_none() || self.at_set(recovery) {
+                break;
+            }
+            match self.try(&parser) {
+                Ok(x) => res.push(x),
+                Err(_) => {
+                    self.err_recover("unexpected token", recovery);
+                }
+            }
+        }
+        O::from_iter(res)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::Cell;
+    use std::collections::HashMap;
 
     struct TP {
         input: Vec<i32>,
         cursor: usize,
-        saved_cursor: usize,
+        // (cursor, events_len, diagnostics_len) at the time of `save`, so
+        // `load` can roll back the events and diagnostics a discarded
+        // branch recorded, not just where it was reading from.
+        checkpoints: Vec<(usize, usize, usize)>,
+        events: Vec<Event<i32>>,
+        diagnostics: Vec<Diagnostic>,
+        memo: HashMap<(u32, usize), MemoOutcome<i32>>,
     }
 
     impl TP {
@@ -124,12 +522,15 @@ mod tests {
             TP {
                 input: input.to_vec(),
                 cursor: 0,
-                saved_cursor: 0,
+                checkpoints: Vec::new(),
+                events: Vec::new(),
+                diagnostics: Vec::new(),
+                memo: HashMap::new(),
             }
         }
     }
 
-    impl Parser<i32, String> for TP {
+    impl Parser<i32, String, i32> for TP {
         fn consume(&mut self) -> Option<i32> {
             match self.input.get(self.cursor) {
                 Some(x) => {
@@ -153,11 +554,76 @@ mod tests {
         }
 
         fn save(&mut self) {
-            self.saved_cursor = self.cursor;
+            self.checkpoints.push((self.cursor, self.events.len(), self.diagnostics.len()));
         }
 
         fn load(&mut self) {
-            self.cursor = self.saved_cursor;
+            let (cursor, events_len, diagnostics_len) =
+                self.checkpoints.pop().expect("unbalanced save/load");
+            self.cursor = cursor;
+            self.truncate_events(events_len);
+            self.truncate_diagnostics(diagnostics_len);
+        }
+
+        fn commit(&mut self) {
+            self.checkpoints.pop().expect("unbalanced save/commit");
+        }
+
+        fn push_event(&mut self, event: Event<i32>) -> usize {
+            self.events.push(event);
+            self.events.len() - 1
+        }
+
+        fn event_mut(&mut self, pos: usize) -> &mut Event<i32> {
+            &mut self.events[pos]
+        }
+
+        fn events_len(&self) -> usize {
+            self.events.len()
+        }
+
+        fn event_at(&self, pos: usize) -> &Event<i32> {
+            &self.events[pos]
+        }
+
+        fn truncate_events(&mut self, len: usize) {
+            self.events.truncate(len);
+        }
+
+        fn push_diagnostic(&mut self, diagnostic: Diagnostic) {
+            self.diagnostics.push(diagnostic);
+        }
+
+        fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+            ::std::mem::replace(&mut self.diagnostics, Vec::new())
+        }
+
+        fn diagnostics_len(&self) -> usize {
+            self.diagnostics.len()
+        }
+
+        fn diagnostic_at(&self, pos: usize) -> &Diagnostic {
+            &self.diagnostics[pos]
+        }
+
+        fn truncate_diagnostics(&mut self, len: usize) {
+            self.diagnostics.truncate(len);
+        }
+
+        fn mark(&self) -> usize {
+            self.cursor
+        }
+
+        fn seek(&mut self, pos: usize) {
+            self.cursor = pos;
+        }
+
+        fn memo_lookup(&self, rule_id: u32, pos: usize) -> Option<&MemoOutcome<i32>> {
+            self.memo.get(&(rule_id, pos))
+        }
+
+        fn memo_store(&mut self, rule_id: u32, pos: usize, outcome: MemoOutcome<i32>) {
+            self.memo.insert((rule_id, pos), outcome);
         }
     }
 
@@ -255,6 +721,18 @@ mod tests {
         assert_eq!(p.try(|p| p.string(vec![2, 4, 6])), Ok(vec![2, 4, 6]));
     }
 
+    #[test]
+    fn try_nested_checkpoints_dont_clobber_outer() {
+        let mut p = TP::new(&[1, 2, 3, 4]);
+        let result = p.try(|p| {
+            let _ = p.try(|p| p.atom(1));
+            let _ = p.try(|p| p.atom(99));
+            p.atom(99)
+        });
+        assert_eq!(result, err("unexpected token 2, expected 99"));
+        assert_eq!(p.next(), Ok(1));
+    }
+
     #[test]
     fn choose_success() {
         let mut p = TP::new(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
@@ -264,6 +742,16 @@ mod tests {
                    Ok(vec![1, 2, 3]));
     }
 
+    #[test]
+    fn choose_discards_rolled_back_alternative_from_event_log() {
+        let mut p = TP::new(&[1, 99]);
+        assert_eq!(p.choose(&[&|p| p.atom(77), &|p| p.atom(1)]), Ok(1));
+        // The failed first alternative consumed and pushed a Token before
+        // failing the atom check; load() must have rolled that back along
+        // with the cursor, leaving only the successful attempt's Token.
+        assert_eq!(p.events, vec![Event::Token]);
+    }
+
     #[test]
     fn choose_success_with_recover() {
         let mut p = TP::new(&[4, 5, 6, 7, 8, 9, 10]);
@@ -333,4 +821,315 @@ mod tests {
         p.optional(|p| p.atom(2));
         assert_eq!(p.string(vec![1, 2, 3]), Ok(vec![1, 2, 3]));
     }
+
+    #[test]
+    fn at_set_success() {
+        let set = TokenSet::new(vec![3, 4]);
+        let p = TP::new(&[3, 5]);
+        assert!(p.at_set(&set));
+    }
+
+    #[test]
+    fn at_set_fail_not_matching() {
+        let set = TokenSet::new(vec![3, 4]);
+        let p = TP::new(&[5, 3]);
+        assert!(!p.at_set(&set));
+    }
+
+    #[test]
+    fn at_set_fail_empty() {
+        let set = TokenSet::new(vec![3, 4]);
+        let p = TP::new(&[]);
+        assert!(!p.at_set(&set));
+    }
+
+    #[test]
+    fn err_recover_skips_to_recovery_set() {
+        let set = TokenSet::new(vec![9]);
+        let mut p = TP::new(&[1, 2, 3, 9, 10]);
+        assert_eq!(p.err_recover("bad token", &set), String::from("bad token"));
+        assert_eq!(p.next(), Ok(9));
+    }
+
+    #[test]
+    fn err_recover_stops_at_eof() {
+        let set = TokenSet::new(vec![9]);
+        let mut p = TP::new(&[1, 2, 3]);
+        assert_eq!(p.err_recover("bad token", &set), String::from("bad token"));
+        assert_eq!(p.next(), err("unexpected eof"));
+    }
+
+    #[test]
+    fn many_recover_success() {
+        let lt5 = |p: &mut TP| -> Result<i32, String> { p.predicate(|x| *x < 5) };
+        let set = TokenSet::new(vec![9]);
+
+        let mut p = TP::new(&[1, 2, 7, 3, 9]);
+        let result: Vec<i32> = p.many_recover(&lt5, &set);
+        assert_eq!(result, vec![1, 2]);
+        assert_eq!(p.next(), Ok(9));
+    }
+
+    #[test]
+    fn marker_complete_records_kind() {
+        let mut p = TP::new(&[1, 2]);
+        let m = p.start();
+        assert_eq!(p.next(), Ok(1));
+        m.complete(&mut p, 42);
+        assert_eq!(p.events,
+                   vec![Event::Start {
+                            kind: Some(42),
+                            forward_parent: None,
+                        },
+                        Event::Token,
+                        Event::Finish]);
+    }
+
+    #[test]
+    fn marker_abandon_truncates_when_nothing_was_parsed() {
+        let mut p = TP::new(&[1, 2]);
+        let m = p.start();
+        m.abandon(&mut p);
+        assert_eq!(p.events, vec![]);
+    }
+
+    #[test]
+    fn marker_abandon_balances_with_finish_when_children_exist() {
+        let mut p = TP::new(&[1, 2]);
+        let m = p.start();
+        assert_eq!(p.next(), Ok(1));
+        m.abandon(&mut p);
+        assert_eq!(p.events,
+                   vec![Event::Start {
+                            kind: None,
+                            forward_parent: None,
+                        },
+                        Event::Token,
+                        Event::Finish]);
+    }
+
+    #[test]
+    fn completed_marker_precede_wraps_in_new_parent() {
+        let mut p = TP::new(&[1, 2]);
+        let inner = p.start();
+        assert_eq!(p.next(), Ok(1));
+        let inner = inner.complete(&mut p, 1);
+        let outer = inner.precede(&mut p);
+        outer.complete(&mut p, 2);
+
+        assert_eq!(p.events,
+                   vec![Event::Start {
+                            kind: Some(1),
+                            forward_parent: Some(3),
+                        },
+                        Event::Token,
+                        Event::Finish,
+                        Event::Start {
+                            kind: Some(2),
+                            forward_parent: None,
+                        },
+                        Event::Finish]);
+    }
+
+    #[test]
+    fn emit_records_diagnostic_at_current_pos() {
+        let mut p = TP::new(&[1, 2]);
+        p.emit("something looks off");
+        assert_eq!(p.take_diagnostics(),
+                   vec![Diagnostic {
+                            pos: (0, 0),
+                            message: String::from("something looks off"),
+                            severity: Severity::Error,
+                        }]);
+    }
+
+    #[test]
+    fn take_diagnostics_drains() {
+        let mut p = TP::new(&[1, 2]);
+        p.emit("first");
+        assert_eq!(p.take_diagnostics().len(), 1);
+        assert_eq!(p.take_diagnostics(), vec![]);
+    }
+
+    #[test]
+    fn parse_combines_recovered_output_with_diagnostics() {
+        let lt5 = |p: &mut TP| -> Result<i32, String> { p.predicate(|x| *x < 5) };
+        let set = TokenSet::new(vec![9]);
+        let mut p = TP::new(&[1, 2, 7, 3, 9]);
+
+        let (output, diagnostics) = p.parse(|p| -> Result<Vec<i32>, String> {
+            Ok(p.many_recover(&lt5, &set))
+        });
+
+        assert_eq!(output, Some(vec![1, 2]));
+        assert_eq!(diagnostics,
+                   vec![Diagnostic {
+                            pos: (0, 0),
+                            message: String::from("unexpected token"),
+                            severity: Severity::Error,
+                        }]);
+    }
+
+    #[test]
+    fn parse_emits_diagnostic_on_hard_failure() {
+        let mut p = TP::new(&[9]);
+
+        let (output, diagnostics) = p.parse(|p| p.predicate(|x| *x < 5));
+
+        assert_eq!(output, None);
+        assert_eq!(diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn err_recover_also_emits_diagnostic() {
+        let set = TokenSet::new(vec![9]);
+        let mut p = TP::new(&[1, 2, 9]);
+        p.err_recover("bad token", &set);
+        assert_eq!(p.take_diagnostics(),
+                   vec![Diagnostic {
+                            pos: (0, 0),
+                            message: String::from("bad token"),
+                            severity: Severity::Error,
+                        }]);
+    }
+
+    // Tokens below zero stand in for operators in these expr_bp tests:
+    // -1 is `-`, -2 is `*` (both left-associative), -3 is `^` (right-associative).
+    fn prefix(p: &mut TP) -> Result<i32, String> {
+        p.predicate(|x| *x >= 0)
+    }
+
+    fn infix_bp(op: &i32) -> Option<(u8, u8)> {
+        match *op {
+            -1 => Some((1, 2)),
+            -2 => Some((3, 4)),
+            -3 => Some((6, 5)),
+            _ => None,
+        }
+    }
+
+    fn fold(lhs: i32, op: i32, rhs: i32) -> i32 {
+        match op {
+            -1 => lhs - rhs,
+            -2 => lhs * rhs,
+            -3 => lhs.pow(rhs as u32),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn expr_bp_respects_precedence() {
+        let mut p = TP::new(&[2, -2, 3, -1, 4]);
+        assert_eq!(p.expr_bp(0, prefix, infix_bp, fold), Ok(2));
+    }
+
+    #[test]
+    fn expr_bp_left_associative() {
+        let mut p = TP::new(&[10, -1, 3, -1, 2]);
+        assert_eq!(p.expr_bp(0, prefix, infix_bp, fold), Ok(5));
+    }
+
+    #[test]
+    fn expr_bp_right_associative() {
+        let mut p = TP::new(&[2, -3, 3, -3, 2]);
+        assert_eq!(p.expr_bp(0, prefix, infix_bp, fold), Ok(512));
+    }
+
+    #[test]
+    fn expr_bp_single_atom() {
+        let mut p = TP::new(&[7]);
+        assert_eq!(p.expr_bp(0, prefix, infix_bp, fold), Ok(7));
+    }
+
+    #[test]
+    fn memoize_caches_success_and_fast_forwards() {
+        let calls = Cell::new(0);
+        let rule = |p: &mut TP| -> Result<Vec<i32>, String> {
+            calls.set(calls.get() + 1);
+            p.string(vec![1, 2])
+        };
+
+        let mut p = TP::new(&[1, 2, 3]);
+        assert_eq!(p.memoize(1, &rule), Ok(vec![1, 2]));
+        assert_eq!(calls.get(), 1);
+
+        p.seek(0);
+        assert_eq!(p.memoize(1, &rule), Ok(vec![1, 2]));
+        assert_eq!(calls.get(), 1);
+        assert_eq!(p.next(), Ok(3));
+    }
+
+    #[test]
+    fn memoize_caches_failure() {
+        let calls = Cell::new(0);
+        let rule = |p: &mut TP| -> Result<i32, String> {
+            calls.set(calls.get() + 1);
+            p.atom(99)
+        };
+
+        let mut p = TP::new(&[1, 2]);
+        assert_eq!(p.memoize(2, &rule),
+                   err("unexpected token 1, expected 99"));
+        assert_eq!(calls.get(), 1);
+
+        p.seek(0);
+        assert_eq!(p.memoize(2, &rule),
+                   err("unexpected token 1, expected 99"));
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn memoize_distinguishes_position() {
+        let calls = Cell::new(0);
+        let rule = |p: &mut TP| -> Result<i32, String> {
+            calls.set(calls.get() + 1);
+            p.next()
+        };
+
+        let mut p = TP::new(&[1, 2]);
+        assert_eq!(p.memoize(3, &rule), Ok(1));
+        assert_eq!(p.memoize(3, &rule), Ok(2));
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn memoize_replays_events_on_cache_hit() {
+        let rule = |p: &mut TP| -> Result<i32, String> { p.next() };
+
+        let mut p = TP::new(&[1, 1]);
+        assert_eq!(p.memoize(4, &rule), Ok(1));
+        assert_eq!(p.events_len(), 1);
+
+        p.seek(0);
+        assert_eq!(p.memoize(4, &rule), Ok(1));
+        assert_eq!(p.events_len(), 2);
+    }
+
+    #[test]
+    fn memoize_remaps_forward_parent_on_replay() {
+        let rule = |p: &mut TP| -> Result<i32, String> {
+            let inner = p.start();
+            let x = try!(p.next());
+            let inner = inner.complete(p, 1);
+            let outer = inner.precede(p);
+            outer.complete(p, 2);
+            Ok(x)
+        };
+
+        let mut p = TP::new(&[1, 1]);
+        assert_eq!(p.memoize(5, &rule), Ok(1));
+        assert_eq!(p.event_at(0),
+                   &Event::Start {
+                        kind: Some(1),
+                        forward_parent: Some(3),
+                    });
+
+        p.seek(0);
+        assert_eq!(p.memoize(5, &rule), Ok(1));
+        assert_eq!(p.event_at(5),
+                   &Event::Start {
+                        kind: Some(1),
+                        forward_parent: Some(8),
+                    });
+    }
 }